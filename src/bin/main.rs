@@ -21,10 +21,13 @@ extern crate serde;
 #[macro_use] extern crate serde_json as sj;
 #[macro_use] extern crate serde_derive;
 
-use std::io::Write;
+use std::io::{Read, Write, ErrorKind};
 use std::str;
 use std::fmt;
 use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread;
+use std::time::Duration;
 use serde::de::{SeqAccess, Visitor, Error};
 
 #[derive(Serialize, Deserialize)]
@@ -76,14 +79,6 @@ fn deserialize_monitor_event_params<'de, D>(deserializer: D)
     deserializer.deserialize_seq(JsonRpcMonitorEventParamsVisitor)
 }
 
-#[derive(Serialize, Deserialize)]
-struct JsonRpcMonitorEvent {
-    id: (),
-    method: String,
-    #[serde(deserialize_with = "deserialize_monitor_event_params")]
-    params: JsonRpcMonitorEventParams,
-}
-
 struct JsonUuidVisitor;
 
 impl JsonUuidVisitor {
@@ -261,6 +256,551 @@ struct JsonDiff {
     tunnel: HashMap<String, JsonDiffVtepTunnel>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+enum AtomicType {
+    Integer,
+    Real,
+    Boolean,
+    String,
+    Uuid,
+}
+
+fn parse_atomic_type(val: &sj::Value) -> Result<AtomicType, String> {
+    match val.as_str() {
+        Some("integer") => Ok(AtomicType::Integer),
+        Some("real") => Ok(AtomicType::Real),
+        Some("boolean") => Ok(AtomicType::Boolean),
+        Some("string") => Ok(AtomicType::String),
+        Some("uuid") => Ok(AtomicType::Uuid),
+        _ => Err(format!("Unrecognized atomic type: {}", val)),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ColumnType {
+    Scalar(AtomicType),
+    Optional(AtomicType),
+    Set(AtomicType),
+    Map(AtomicType, AtomicType),
+}
+
+fn parse_column_type(val: &sj::Value) -> Result<ColumnType, String> {
+    if let sj::Value::String(_) = *val {
+        return Ok(ColumnType::Scalar(parse_atomic_type(val) ?));
+    }
+
+    let obj = val.as_object()
+        .ok_or_else(|| format!("Malformed column type: {}", val)) ?;
+    let key_type = parse_atomic_type(obj.get("key")
+        .ok_or_else(|| format!("Column type missing \"key\": {}", val)) ?) ?;
+
+    if let Some(value) = obj.get("value") {
+        return Ok(ColumnType::Map(key_type, parse_atomic_type(value) ?));
+    }
+
+    let min = obj.get("min").and_then(|m| m.as_u64()).unwrap_or(1);
+    let is_many = match obj.get("max") {
+        Some(&sj::Value::String(ref s)) if s == "unlimited" => true,
+        Some(m) => m.as_u64().map_or(false, |n| n > 1),
+        None => false,
+    };
+
+    if is_many {
+        return Ok(ColumnType::Set(key_type));
+    }
+    if min == 0 {
+        return Ok(ColumnType::Optional(key_type));
+    }
+    return Ok(ColumnType::Scalar(key_type));
+}
+
+#[derive(Debug, Clone)]
+enum TypedValue {
+    Integer(i64),
+    Real(f64),
+    Boolean(bool),
+    String(String),
+    Uuid(String),
+    Set(Vec<TypedValue>),
+    Map(Vec<(TypedValue, TypedValue)>),
+    Null,
+}
+
+fn decode_atom(atomic: &AtomicType, val: &sj::Value) -> Result<TypedValue, String> {
+    match *atomic {
+        AtomicType::Integer => {
+            Ok(TypedValue::Integer(val.as_i64()
+                .ok_or_else(|| format!("Expected integer, got {}", val)) ?))
+        }
+        AtomicType::Real => {
+            Ok(TypedValue::Real(val.as_f64()
+                .ok_or_else(|| format!("Expected real, got {}", val)) ?))
+        }
+        AtomicType::Boolean => {
+            Ok(TypedValue::Boolean(val.as_bool()
+                .ok_or_else(|| format!("Expected boolean, got {}", val)) ?))
+        }
+        AtomicType::String => {
+            Ok(TypedValue::String(val.as_str()
+                .ok_or_else(|| format!("Expected string, got {}", val)) ?.to_string()))
+        }
+        AtomicType::Uuid => {
+            Ok(TypedValue::Uuid(parse_uuid_value(val) ?))
+        }
+    }
+}
+
+// Unwraps a ["set", [...]] envelope into its elements; a set of exactly
+// one element may instead appear on the wire as the bare atom.
+fn unwrap_set_envelope(val: &sj::Value) -> Result<Vec<sj::Value>, String> {
+    if let sj::Value::Array(ref v) = *val {
+        if v.len() == 2 && v[0].as_str() == Some("set") {
+            let items = v[1].as_array()
+                .ok_or_else(|| format!("Malformed JSON RPC set: {}", val)) ?;
+            return Ok(items.clone());
+        }
+    }
+    return Ok(vec![val.clone()]);
+}
+
+fn unwrap_map_envelope(val: &sj::Value) -> Result<Vec<(sj::Value, sj::Value)>, String> {
+    let pairs = match *val {
+        sj::Value::Array(ref v) if v.len() == 2 && v[0].as_str() == Some("map") => {
+            v[1].as_array().ok_or_else(|| format!("Malformed JSON RPC map: {}", val)) ?
+        }
+        _ => return Err(format!("Malformed JSON RPC map: {}", val)),
+    };
+
+    let mut ret = Vec::new();
+    for pair in pairs {
+        let pair = pair.as_array()
+            .ok_or_else(|| format!("Malformed JSON RPC map entry: {}", pair)) ?;
+        if pair.len() != 2 {
+            return Err(format!("Malformed JSON RPC map entry: {:?}", pair));
+        }
+        ret.push((pair[0].clone(), pair[1].clone()));
+    }
+    return Ok(ret);
+}
+
+fn decode_column(column_type: &ColumnType, val: &sj::Value) -> Result<TypedValue, String> {
+    match *column_type {
+        ColumnType::Scalar(ref atomic) => decode_atom(atomic, val),
+
+        ColumnType::Optional(ref atomic) => {
+            let items = unwrap_set_envelope(val) ?;
+            match items.len() {
+                0 => Ok(TypedValue::Null),
+                1 => decode_atom(atomic, &items[0]),
+                _ => Err(format!("Optional column holds more than one value: {}", val)),
+            }
+        }
+
+        ColumnType::Set(ref atomic) => {
+            let items = unwrap_set_envelope(val) ?;
+            let mut ret = Vec::new();
+            for item in &items {
+                ret.push(decode_atom(atomic, item) ?);
+            }
+            Ok(TypedValue::Set(ret))
+        }
+
+        ColumnType::Map(ref key_type, ref value_type) => {
+            let pairs = unwrap_map_envelope(val) ?;
+            let mut ret = Vec::new();
+            for (k, v) in pairs {
+                ret.push((decode_atom(key_type, &k) ?, decode_atom(value_type, &v) ?));
+            }
+            Ok(TypedValue::Map(ret))
+        }
+    }
+}
+
+struct TableSchema {
+    columns: HashMap<String, ColumnType>,
+}
+
+impl TableSchema {
+    // Columns the schema doesn't know about (e.g. "_uuid") are left out
+    // rather than rejected.
+    fn decode_row(&self, row: &sj::Value) -> Result<HashMap<String, TypedValue>, String> {
+        let row_obj = row.as_object()
+            .ok_or_else(|| format!("Malformed row: {}", row)) ?;
+
+        let mut ret = HashMap::new();
+        for (column, value) in row_obj {
+            if let Some(column_type) = self.columns.get(column) {
+                ret.insert(column.clone(), decode_column(column_type, value) ?);
+            }
+        }
+        return Ok(ret);
+    }
+}
+
+struct DatabaseSchema {
+    tables: HashMap<String, TableSchema>,
+}
+
+fn parse_database_schema(val: sj::Value) -> Result<DatabaseSchema, String> {
+    let tables_obj = val.get("tables")
+        .and_then(|t| t.as_object())
+        .ok_or_else(|| format!("Malformed schema: missing \"tables\": {}", val)) ?;
+
+    let mut tables = HashMap::new();
+    for (table, table_val) in tables_obj {
+        let columns_obj = table_val.get("columns")
+            .and_then(|c| c.as_object())
+            .ok_or_else(|| format!("Malformed schema table {}: missing \"columns\"", table)) ?;
+
+        let mut columns = HashMap::new();
+        for (column, column_val) in columns_obj {
+            let type_val = column_val.get("type")
+                .ok_or_else(|| format!("Malformed schema column {}.{}: missing \"type\"", table, column)) ?;
+            columns.insert(column.clone(), parse_column_type(type_val) ?);
+        }
+
+        tables.insert(table.clone(), TableSchema { columns: columns });
+    }
+
+    return Ok(DatabaseSchema { tables: tables });
+}
+
+enum UuidRef {
+    Uuid(String),
+    Named(String),
+}
+
+impl UuidRef {
+    fn to_json(&self) -> sj::Value {
+        match *self {
+            UuidRef::Uuid(ref uuid) => json!(["uuid", uuid]),
+            UuidRef::Named(ref name) => json!(["named-uuid", name]),
+        }
+    }
+}
+
+fn json_uuid_set(refs: &[UuidRef]) -> sj::Value {
+    json!(["set", refs.iter().map(UuidRef::to_json).collect::<Vec<_>>()])
+}
+
+fn physical_switch_row(name: Option<&str>, ports: &[UuidRef],
+                        tunnel_ips: Option<&str>, tunnels: &[UuidRef]) -> sj::Value {
+    let mut row = sj::Map::new();
+    if let Some(name) = name {
+        row.insert("name".to_string(), json!(name));
+    }
+    if !ports.is_empty() {
+        row.insert("ports".to_string(), json_uuid_set(ports));
+    }
+    if let Some(tunnel_ips) = tunnel_ips {
+        row.insert("tunnel_ips".to_string(), json!(tunnel_ips));
+    }
+    if !tunnels.is_empty() {
+        row.insert("tunnels".to_string(), json_uuid_set(tunnels));
+    }
+    return sj::Value::Object(row);
+}
+
+fn physical_locator_row(dst_ip: Option<&str>, encapsulation_type: Option<&str>) -> sj::Value {
+    let mut row = sj::Map::new();
+    if let Some(dst_ip) = dst_ip {
+        row.insert("dst_ip".to_string(), json!(dst_ip));
+    }
+    if let Some(encapsulation_type) = encapsulation_type {
+        row.insert("encapsulation_type".to_string(), json!(encapsulation_type));
+    }
+    return sj::Value::Object(row);
+}
+
+fn physical_locator_set_row(locators: &[UuidRef]) -> sj::Value {
+    return json!({"locators": json_uuid_set(locators)});
+}
+
+fn tunnel_row(local: &UuidRef, remote: &UuidRef) -> sj::Value {
+    return json!({"local": local.to_json(), "remote": remote.to_json()});
+}
+
+fn mcast_macs_remote_row(mac: &str, locator_set: &UuidRef) -> sj::Value {
+    return json!({"MAC": mac, "locator_set": locator_set.to_json()});
+}
+
+// The OVSDB operation model (RFC 7047 section 5.2).
+enum Operation {
+    Insert { table: String, row: sj::Value, uuid_name: Option<String> },
+    Update { table: String, where_: sj::Value, row: sj::Value },
+    Delete { table: String, where_: sj::Value },
+    Mutate { table: String, where_: sj::Value, mutations: sj::Value },
+    Select { table: String, where_: sj::Value, columns: Vec<String> },
+    Wait { table: String, where_: sj::Value, columns: Vec<String>, rows: Vec<sj::Value>, until: String },
+}
+
+impl Operation {
+    fn to_json(&self) -> sj::Value {
+        match *self {
+            Operation::Insert { ref table, ref row, ref uuid_name } => {
+                let mut op = json!({"op": "insert", "table": table, "row": row});
+                if let Some(ref uuid_name) = *uuid_name {
+                    op["uuid-name"] = json!(uuid_name);
+                }
+                return op;
+            }
+
+            Operation::Update { ref table, ref where_, ref row } => {
+                return json!({"op": "update", "table": table, "where": where_, "row": row});
+            }
+
+            Operation::Delete { ref table, ref where_ } => {
+                return json!({"op": "delete", "table": table, "where": where_});
+            }
+
+            Operation::Mutate { ref table, ref where_, ref mutations } => {
+                return json!({"op": "mutate", "table": table, "where": where_, "mutations": mutations});
+            }
+
+            Operation::Select { ref table, ref where_, ref columns } => {
+                return json!({"op": "select", "table": table, "where": where_, "columns": columns});
+            }
+
+            Operation::Wait { ref table, ref where_, ref columns, ref rows, ref until } => {
+                return json!({"op": "wait", "table": table, "where": where_, "columns": columns,
+                               "rows": rows, "until": until, "timeout": 0});
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum OperationResult {
+    Uuid(String),
+    Count(u64),
+    Rows(Vec<sj::Value>),
+    Empty,
+}
+
+fn parse_uuid_value(val: &sj::Value) -> Result<String, String> {
+    if let sj::Value::Array(ref v) = *val {
+        if v.len() == 2 {
+            if let (Some(head), Some(uuid)) = (v[0].as_str(), v[1].as_str()) {
+                if head == "uuid" {
+                    return Ok(uuid.to_string());
+                }
+            }
+        }
+    }
+
+    return Err(format!("expected [\"uuid\", <uuid>], got {}", val));
+}
+
+fn parse_operation_result(val: sj::Value) -> Result<OperationResult, String> {
+    if let Some(error) = val.get("error") {
+        let details = val.get("details").and_then(|d| d.as_str()).unwrap_or("");
+        return Err(format!("Transaction operation failed: {}, {}", error, details));
+    }
+    if let Some(uuid) = val.get("uuid") {
+        return Ok(OperationResult::Uuid(parse_uuid_value(uuid) ?));
+    }
+    if let Some(count) = val.get("count") {
+        let count = count.as_u64()
+            .ok_or_else(|| format!("Malformed transaction count: {}", count)) ?;
+        return Ok(OperationResult::Count(count));
+    }
+    if let Some(rows) = val.get("rows") {
+        let rows = rows.as_array()
+            .ok_or_else(|| format!("Malformed transaction rows: {}", rows)) ?
+            .clone();
+        return Ok(OperationResult::Rows(rows));
+    }
+
+    return Ok(OperationResult::Empty);
+}
+
+struct Condition {
+    column: String,
+    function: String,
+    value: sj::Value,
+}
+
+impl Condition {
+    fn to_json(&self) -> sj::Value {
+        return json!([self.column, self.function, self.value]);
+    }
+}
+
+fn monitor_cond_request(tables: &[(&str, &[String], &[Condition])]) -> sj::Value {
+    let mut obj = sj::Map::new();
+    for &(table, columns, conditions) in tables {
+        let request = json!({
+            "columns": columns,
+            "where": conditions.iter().map(Condition::to_json).collect::<Vec<_>>(),
+        });
+        obj.insert(table.to_string(), json!([request]));
+    }
+    return sj::Value::Object(obj);
+}
+
+#[derive(Debug, Clone)]
+enum RowChange2 {
+    Initial(sj::Value),
+    Insert(sj::Value),
+    Delete(sj::Value),
+    Modify(sj::Value),
+}
+
+fn parse_row_change2(val: sj::Value) -> Result<RowChange2, String> {
+    let obj = match val {
+        sj::Value::Object(obj) => obj,
+        _ => return Err(format!("Malformed update2 row change: {}", val)),
+    };
+
+    if let Some(row) = obj.get("initial") {
+        return Ok(RowChange2::Initial(row.clone()));
+    }
+    if let Some(row) = obj.get("insert") {
+        return Ok(RowChange2::Insert(row.clone()));
+    }
+    if let Some(row) = obj.get("delete") {
+        return Ok(RowChange2::Delete(row.clone()));
+    }
+    if let Some(row) = obj.get("modify") {
+        return Ok(RowChange2::Modify(row.clone()));
+    }
+
+    return Err(format!("Unrecognized update2 row change: {:?}", obj));
+}
+
+// table name -> row uuid -> change.
+type TableUpdates2 = HashMap<String, HashMap<String, RowChange2>>;
+
+fn parse_table_updates2(val: sj::Value) -> Result<TableUpdates2, String> {
+    let tables = val.as_object()
+        .ok_or_else(|| format!("Malformed update2 table updates: {}", val)) ?;
+
+    let mut ret = HashMap::new();
+    for (table, rows) in tables {
+        let rows_obj = rows.as_object()
+            .ok_or_else(|| format!("Malformed update2 rows for table {}: {}", table, rows)) ?;
+
+        let mut row_changes = HashMap::new();
+        for (uuid, change) in rows_obj {
+            row_changes.insert(uuid.clone(), parse_row_change2(change.clone()) ?);
+        }
+        ret.insert(table.clone(), row_changes);
+    }
+    return Ok(ret);
+}
+
+// Each diff element toggles membership in the cached set.
+fn apply_set_diff(old: Option<&sj::Value>, diff: &sj::Value) -> Result<sj::Value, String> {
+    let mut items = match old {
+        Some(old) => unwrap_set_envelope(old) ?,
+        None => Vec::new(),
+    };
+    for item in unwrap_set_envelope(diff) ? {
+        match items.iter().position(|i| *i == item) {
+            Some(pos) => { items.remove(pos); }
+            None => { items.push(item); }
+        }
+    }
+    return Ok(json!(["set", items]));
+}
+
+// A diff entry matching the current value for its key means that key was
+// removed; any other diff entry means added or changed.
+fn apply_map_diff(old: Option<&sj::Value>, diff: &sj::Value) -> Result<sj::Value, String> {
+    let mut pairs = match old {
+        Some(old) => unwrap_map_envelope(old) ?,
+        None => Vec::new(),
+    };
+    for (key, value) in unwrap_map_envelope(diff) ? {
+        match pairs.iter().position(|&(ref k, _)| *k == key) {
+            Some(pos) if pairs[pos].1 == value => { pairs.remove(pos); }
+            Some(pos) => { pairs[pos].1 = value; }
+            None => { pairs.push((key, value)); }
+        }
+    }
+    let encoded: Vec<sj::Value> = pairs.into_iter().map(|(k, v)| json!([k, v])).collect();
+    return Ok(json!(["map", encoded]));
+}
+
+struct TableCache {
+    rows: HashMap<String, sj::Value>,
+}
+
+impl TableCache {
+    fn new() -> TableCache {
+        return TableCache { rows: HashMap::new() };
+    }
+
+    // `schema` distinguishes Set/Map columns, whose "modify" changes are
+    // diffs, from others, whose "modify" changes are literal replacements.
+    fn apply(&mut self, schema: &TableSchema, uuid: &str, change: RowChange2) -> Result<(), String> {
+        match change {
+            RowChange2::Initial(row) | RowChange2::Insert(row) => {
+                self.rows.insert(uuid.to_string(), row);
+            }
+
+            RowChange2::Delete(_) => {
+                self.rows.remove(uuid);
+            }
+
+            RowChange2::Modify(patch) => {
+                let patch_obj = patch.as_object()
+                    .ok_or_else(|| format!("Malformed update2 modify row: {}", patch)) ?;
+                let row = self.rows.entry(uuid.to_string())
+                    .or_insert_with(|| sj::Value::Object(sj::Map::new()));
+                let row_obj = row.as_object_mut()
+                    .ok_or_else(|| format!("Cached row {} is not an object", uuid)) ?;
+                for (column, diff) in patch_obj {
+                    let new_value = match schema.columns.get(column) {
+                        Some(&ColumnType::Set(_)) => apply_set_diff(row_obj.get(column), diff) ?,
+                        Some(&ColumnType::Map(..)) => apply_map_diff(row_obj.get(column), diff) ?,
+                        _ => diff.clone(),
+                    };
+                    row_obj.insert(column.clone(), new_value);
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+// Classifies an inbound JSON RPC object by shape rather than by a fixed
+// struct, since the same stream carries three different message kinds:
+// responses to our own requests (have "result"/"error"), notifications
+// the server sends unprompted (have "method", "id" is null -- e.g. monitor
+// updates), and requests the server expects us to answer (have "method",
+// "id" is not null -- e.g. the periodic "echo" keepalive).
+enum JsonRpcInbound {
+    Response(JsonRpcResult),
+    Notification { method: String, params: sj::Value },
+    Request { id: sj::Value, method: String, params: sj::Value },
+}
+
+impl JsonRpcInbound {
+    fn classify(val: sj::Value) -> Result<JsonRpcInbound, String> {
+        let id = val.get("id").cloned().unwrap_or(sj::Value::Null);
+        let method = val.get("method").and_then(|m| m.as_str()).map(str::to_string);
+        let params = val.get("params").cloned().unwrap_or(sj::Value::Null);
+
+        if let Some(method) = method {
+            if id.is_null() {
+                return Ok(JsonRpcInbound::Notification { method: method, params: params });
+            } else {
+                return Ok(JsonRpcInbound::Request { id: id, method: method, params: params });
+            }
+        }
+
+        if val.get("result").is_some() || val.get("error").is_some() {
+            let result: JsonRpcResult = sj::from_value(val)
+                .map_err(|err| format!("Malformed JSON RPC response: {}", err)) ?;
+            return Ok(JsonRpcInbound::Response(result));
+        }
+
+        return Err(format!("Unrecognized JSON RPC message: {}", val));
+    }
+}
+
 fn jsonrpc_result(expect_id: u32, result: JsonRpcResult) -> Result<sj::Value, String> {
     if result.error.is_some() {
         let error = result.error.unwrap();
@@ -275,33 +815,347 @@ fn jsonrpc_result(expect_id: u32, result: JsonRpcResult) -> Result<sj::Value, St
     return Result::Ok(result.result.unwrap());
 }
 
-fn jsonrpc_communicate(stream: &mut unix_socket::UnixStream,
-                       method: &str, params: sj::Value) -> Result<sj::Value, String> {
-    let request = json!({
-        "id": 0,
-        "method": method,
-        "params": params,
-    });
+// Incrementally extracts top-level JSON values out of a byte stream
+// without blocking, unlike `serde_json::StreamDeserializer`.
+struct JsonFrameReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: Read> JsonFrameReader<R> {
+    fn new(reader: R) -> JsonFrameReader<R> {
+        return JsonFrameReader { reader: reader, buf: Vec::new() };
+    }
+
+    // Returns the end (exclusive) of the first complete top-level JSON
+    // object or array at the front of `buf`, or `None` if `buf` doesn't yet
+    // hold one.  Bare top-level scalars are not recognized.
+    fn find_value_end(buf: &[u8]) -> Option<usize> {
+        let mut depth = 0i32;
+        let mut started = false;
+        let mut in_string = false;
+        let mut escaped = false;
+
+        for (i, &b) in buf.iter().enumerate() {
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if b == b'\\' {
+                    escaped = true;
+                } else if b == b'"' {
+                    in_string = false;
+                }
+                continue;
+            }
+
+            match b {
+                b'"' => { in_string = true; started = true; }
+                b'{' | b'[' => { depth += 1; started = true; }
+                b'}' | b']' => {
+                    depth -= 1;
+                    if started && depth == 0 {
+                        return Some(i + 1);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        return None;
+    }
+
+    fn take_value(&mut self, end: usize) -> Result<sj::Value, String> {
+        let value = sj::from_slice(&self.buf[..end])
+            .map_err(|err| format!("JSON RPC parse error: {}", err)) ?;
+        self.buf.drain(..end);
+        return Ok(value);
+    }
+
+    // Returns `Ok(None)` on `ErrorKind::WouldBlock` instead of blocking;
+    // returns `Err` on EOF so a closed connection isn't mistaken for that.
+    fn try_next(&mut self) -> Result<Option<sj::Value>, String> {
+        if let Some(end) = Self::find_value_end(&self.buf) {
+            return Ok(Some(self.take_value(end) ?));
+        }
+
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.reader.read(&mut chunk) {
+                Ok(0) => {
+                    return Err("OVSDB connection closed".to_string());
+                }
+
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    if let Some(end) = Self::find_value_end(&self.buf) {
+                        return Ok(Some(self.take_value(end) ?));
+                    }
+                }
+
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    return Ok(None);
+                }
+
+                Err(err) => {
+                    return Err(format!("Failed to read from OVSDB socket: {}", err));
+                }
+            }
+        }
+    }
+}
+
+impl<R: AsRawFd> AsRawFd for JsonFrameReader<R> {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.reader.as_raw_fd();
+    }
+}
+
+// A connection to ovsdb-server.  Requests are assigned ids out of a
+// monotonic counter, so several requests (e.g. "list_dbs", "get_schema",
+// "monitor") can be outstanding at once instead of forcing one strictly
+// serial request/response round-trip as the old fixed "id 0" scheme did.
+// Responses that arrive for ids nobody is waiting on yet -- because the
+// server answers out of order, or because a notification or echo request
+// is interleaved on the same stream -- are buffered in `pending` until
+// the matching `recv` call claims them.
+struct Client {
+    writer: unix_socket::UnixStream,
+    reader: JsonFrameReader<unix_socket::UnixStream>,
+    next_id: u32,
+    pending: HashMap<u32, JsonRpcResult>,
+    nonblocking: bool,
+}
+
+impl Client {
+    fn connect(path: &str) -> Result<Client, String> {
+        let stream = unix_socket::UnixStream::connect(path)
+            .map_err(|err| format!("Couldn't open OVSDB socket: {}", err)) ?;
+        let writer = stream.try_clone()
+            .map_err(|err| format!("Failed to clone OVSDB socket: {}", err)) ?;
+
+        return Ok(Client {
+            writer: writer,
+            reader: JsonFrameReader::new(stream),
+            next_id: 0,
+            pending: HashMap::new(),
+            nonblocking: false,
+        });
+    }
+
+    fn set_nonblocking(&mut self, nonblocking: bool) -> Result<(), String> {
+        self.writer.set_nonblocking(nonblocking)
+            .map_err(|err| format!("Failed to set non-blocking mode: {}", err)) ?;
+        self.nonblocking = nonblocking;
+        return Ok(());
+    }
+
+    // Like `next_notification`, but in non-blocking mode (see
+    // `set_nonblocking`) returns `Ok(None)` instead of blocking.  In the
+    // default blocking mode it blocks just as `next_notification` does.
+    fn poll(&mut self) -> Result<Option<(String, sj::Value)>, String> {
+        loop {
+            let val = match self.reader.try_next() ? {
+                Some(val) => val,
+                None => return Ok(None),
+            };
+
+            if let Some(notification) = self.dispatch(val) ? {
+                return Ok(Some(notification));
+            }
+        }
+    }
+
+    // Sends a request and returns the id it was allocated.  The caller
+    // claims the response later via `recv`.
+    fn call(&mut self, method: &str, params: sj::Value) -> Result<u32, String> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let request = json!({
+            "id": id,
+            "method": method,
+            "params": params,
+        });
+
+        self.writer.write_all(request.to_string().as_bytes())
+            .map_err(|err| format!("Failed to write request: {}", err)) ?;
+
+        return Ok(id);
+    }
+
+    // Reads and dispatches inbound messages until the response to `id`
+    // shows up.  Responses for other in-flight calls are buffered for a
+    // later `recv`, and echo requests are answered inline so they never
+    // need to reach the caller.
+    fn recv(&mut self, id: u32) -> Result<sj::Value, String> {
+        loop {
+            if let Some(result) = self.pending.remove(&id) {
+                return jsonrpc_result(id, result);
+            }
+
+            self.handle_one() ?;
+        }
+    }
+
+    fn communicate(&mut self, method: &str, params: sj::Value) -> Result<sj::Value, String> {
+        let id = self.call(method, params) ?;
+        return self.recv(id);
+    }
+
+    fn transact(&mut self, db: &str, ops: &[Operation]) -> Result<Vec<OperationResult>, String> {
+        let mut params = vec![json!(db)];
+        for op in ops {
+            params.push(op.to_json());
+        }
+
+        let result = self.communicate("transact", sj::Value::Array(params)) ?;
+        let results = result.as_array()
+            .ok_or_else(|| format!("Malformed transact response: {}", result)) ?;
 
-    stream.write_all(request.to_string().as_bytes())
-        .map_err(|err| format!("Failed to write request: {}", err)) ?;
+        let mut ret = Vec::new();
+        for r in results {
+            ret.push(parse_operation_result(r.clone()) ?);
+        }
+        return Ok(ret);
+    }
+
+    // Updates arrive as "update2" notifications (see `parse_table_updates2`).
+    fn monitor_cond(&mut self, db: &str, monitor_id: &str, request: sj::Value) -> Result<sj::Value, String> {
+        return self.communicate("monitor_cond", json!([db, monitor_id, request]));
+    }
 
-    for val in sj::Deserializer::from_reader(stream).into_iter::<JsonRpcResult>() {
-        let res = val.map_err(|err| format!("JSON RPC error: {}", err)) ?;
-        return jsonrpc_result(0, res);
+    fn get_schema(&mut self, db: &str) -> Result<DatabaseSchema, String> {
+        let result = self.communicate("get_schema", json!([db])) ?;
+        return parse_database_schema(result);
+    }
+
+    // Reads inbound messages until a server notification (e.g. a monitor
+    // update) shows up, dispatching responses and echo requests along the
+    // way the same way `recv` does.  Returns the notification's method
+    // ("update", "update2", ...) along with its params.
+    fn next_notification(&mut self) -> Result<(String, sj::Value), String> {
+        loop {
+            if let Some(notification) = self.handle_one() ? {
+                return Ok(notification);
+            }
+        }
+    }
+
+    // Sleeps between retries in non-blocking mode, to avoid busy-spinning.
+    fn handle_one(&mut self) -> Result<Option<(String, sj::Value)>, String> {
+        loop {
+            match self.reader.try_next() ? {
+                Some(val) => return self.dispatch(val),
+                None => {
+                    if self.nonblocking {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                }
+            }
+        }
+    }
+
+    fn dispatch(&mut self, val: sj::Value) -> Result<Option<(String, sj::Value)>, String> {
+        match JsonRpcInbound::classify(val) ? {
+            JsonRpcInbound::Response(result) => {
+                self.pending.insert(result.id, result);
+                return Ok(None);
+            }
+
+            JsonRpcInbound::Request { id, method, params } => {
+                if method != "echo" {
+                    return Err(format!("Unexpected JSON RPC request: {}", method));
+                }
+
+                let reply = json!({"id": id, "result": params, "error": sj::Value::Null});
+                self.writer.write_all(reply.to_string().as_bytes())
+                    .map_err(|err| format!("Failed to write echo reply: {}", err)) ?;
+                return Ok(None);
+            }
+
+            JsonRpcInbound::Notification { method, params } => {
+                return Ok(Some((method, params)));
+            }
+        }
     }
-    return Result::Err("No JSON RPC response.".to_string());
+}
+
+impl AsRawFd for Client {
+    fn as_raw_fd(&self) -> RawFd {
+        return self.reader.as_raw_fd();
+    }
+}
+
+// Demonstrates the intended non-blocking consumer pattern.
+fn poll_loop(client: &mut Client, iterations: u32) -> Result<(), String> {
+    client.set_nonblocking(true) ?;
+    for _ in 0..iterations {
+        while let Some((method, params)) = client.poll() ? {
+            println!("poll: {} {}", method, params);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    client.set_nonblocking(false) ?;
+    return Ok(());
 }
 
 fn main2() -> Result<(), String> {
-    let mut stream = unix_socket::UnixStream::connect("/var/run/openvswitch/db.sock")
-        .map_err(|err| format!("Couldn't open OVSDB socket: {}", err)) ?;
+    let mut client = Client::connect("/var/run/openvswitch/db.sock") ?;
 
     {
-        let result = jsonrpc_communicate(&mut stream, "echo", json!(["Hello", "OVSDB", "?"])) ?;
+        let result = client.communicate("echo", json!(["Hello", "OVSDB", "?"])) ?;
         println!("{}", result);
     }
 
+    let hardware_vtep_schema = client.get_schema("hardware_vtep") ?;
+    if let Some(physical_switch) = hardware_vtep_schema.tables.get("Physical_Switch") {
+        println!("Physical_Switch columns: {:?}", physical_switch.columns);
+    }
+
+    {
+        // Two locators, a tunnel between them, a switch that owns the
+        // tunnel, and a locator set grouping both locators for a
+        // Mcast_Macs_Remote entry -- all in one transaction, referencing
+        // the not-yet-assigned rows by uuid-name.
+        let locator0 = Operation::Insert {
+            table: "Physical_Locator".to_string(),
+            row: physical_locator_row(Some("10.0.0.1"), Some("vxlan_over_ipv4")),
+            uuid_name: Some("locator0".to_string()),
+        };
+        let locator1 = Operation::Insert {
+            table: "Physical_Locator".to_string(),
+            row: physical_locator_row(Some("10.0.0.2"), Some("vxlan_over_ipv4")),
+            uuid_name: Some("locator1".to_string()),
+        };
+        let locator_set = Operation::Insert {
+            table: "Physical_Locator_Set".to_string(),
+            row: physical_locator_set_row(&[UuidRef::Named("locator0".to_string()),
+                                            UuidRef::Named("locator1".to_string())]),
+            uuid_name: Some("locator_set0".to_string()),
+        };
+        let tunnel = Operation::Insert {
+            table: "Tunnel".to_string(),
+            row: tunnel_row(&UuidRef::Named("locator0".to_string()),
+                             &UuidRef::Named("locator1".to_string())),
+            uuid_name: Some("tunnel0".to_string()),
+        };
+        let switch = Operation::Insert {
+            table: "Physical_Switch".to_string(),
+            row: physical_switch_row(Some("sw0"), &[], Some("10.0.0.1"),
+                                      &[UuidRef::Named("tunnel0".to_string())]),
+            uuid_name: None,
+        };
+        let mcast = Operation::Insert {
+            table: "Mcast_Macs_Remote".to_string(),
+            row: mcast_macs_remote_row("unknown-dst", &UuidRef::Named("locator_set0".to_string())),
+            uuid_name: None,
+        };
+
+        let results = client.transact("hardware_vtep",
+                                       &[locator0, locator1, locator_set, tunnel, switch, mcast]) ?;
+        println!("transact results: {:?}", results);
+    }
+
     {
         let mon = json!(["hardware_vtep", "hardware_vtep",
                          {
@@ -318,7 +1172,7 @@ fn main2() -> Result<(), String> {
                                  "columns": ["local", "remote"],
                              }
                          }]);
-        let result = jsonrpc_communicate(&mut stream, "monitor", mon) ?;
+        let result = client.communicate("monitor", mon) ?;
         println!("{:?}\n---", result);
         let d: JsonDiff = sj::from_value(result).unwrap();
         println!("{:?}", d);
@@ -331,27 +1185,60 @@ fn main2() -> Result<(), String> {
                                  "columns": ["name", "type", "ofport"],
                              },
                          }]);
-        let result = jsonrpc_communicate(&mut stream, "monitor", mon) ?;
+        let result = client.communicate("monitor", mon) ?;
+        println!("{}", result);
+    }
+
+    {
+        // Subscribe to just the Physical_Switch rows for a given tunnel endpoint.
+        let cond = Condition { column: "tunnel_ips".to_string(),
+                                function: "==".to_string(),
+                                value: json!("10.0.0.1") };
+        let columns = vec!["name".to_string(), "tunnel_ips".to_string()];
+        let request = monitor_cond_request(&[("Physical_Switch", &columns, &[cond])]);
+        let result = client.monitor_cond("hardware_vtep", "hardware_vtep_cond", request) ?;
         println!("{}", result);
     }
 
-    for val in sj::Deserializer::from_reader(stream).into_iter::<JsonRpcMonitorEvent>() {
-        let res = val.map_err(|err| format!("JSON RPC error: {}", err)) ?;
-        if let sj::Value::String(ref dbname) = res.params.key {
+    poll_loop(&mut client, 5) ?;
+
+    let mut physical_switches = TableCache::new();
+
+    loop {
+        let (method, params) = client.next_notification() ?;
+        if method == "update2" {
+            let event_params = deserialize_monitor_event_params(params)
+                .map_err(|err: sj::Error| format!("Malformed update2 notification: {}", err)) ?;
+            let table_updates = parse_table_updates2(event_params.updates) ?;
+            if let Some(physical_switch) = hardware_vtep_schema.tables.get("Physical_Switch") {
+                if let Some(rows) = table_updates.get("Physical_Switch") {
+                    for (uuid, change) in rows {
+                        physical_switches.apply(physical_switch, uuid, change.clone()) ?;
+                    }
+                }
+                for (uuid, row) in &physical_switches.rows {
+                    let decoded = physical_switch.decode_row(row) ?;
+                    println!("Physical_Switch {}: {:?}", uuid, decoded);
+                }
+            }
+            continue;
+        }
+
+        let event_params = deserialize_monitor_event_params(params)
+            .map_err(|err: sj::Error| format!("Malformed monitor update: {}", err)) ?;
+        if let sj::Value::String(ref dbname) = event_params.key {
             if dbname == "hardware_vtep" {
-                let d: JsonDiff = sj::from_value(res.params.updates).unwrap();
+                let d: JsonDiff = sj::from_value(event_params.updates).unwrap();
                 println!("VTEP update: {:?}", d);
             } else if dbname == "Open_vSwitch" {
-                println!("OVS update: {:?}", res.params.updates);
+                println!("OVS update: {:?}", event_params.updates);
             } else {
                 return Err(format!("Monitor event relating to an unknown database {}", dbname));
             }
         } else {
-            return Err(format!("Invalid monitor event key: {}", res.params.key));
+            return Err(format!("Invalid monitor event key: {}", event_params.key));
         }
     }
-
-    Result::Ok(())
 }
 
 fn main() {